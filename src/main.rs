@@ -1,16 +1,31 @@
+use base64::Engine;
 use clap::{Parser, Subcommand};
+use futures_util::stream::{SplitSink, SplitStream};
 use futures_util::{SinkExt, StreamExt};
+use std::fs::File;
+use std::io::BufReader;
 use std::net::SocketAddr;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::select;
+use tokio::sync::Mutex;
+use tokio::time;
 use tokio::{
     net::{TcpListener, TcpStream},
     spawn,
 };
+use tokio_rustls::rustls;
 use tokio_tungstenite::tungstenite::Message;
-use tokio_tungstenite::{accept_async, connect_async_with_config};
+use tokio_tungstenite::{
+    accept_async, connect_async_tls_with_config, Connector, MaybeTlsStream, WebSocketStream,
+};
+
+mod mux;
 
 const BUFFER_SIZE: usize = 1024;
+const DEFAULT_BUFFER_SIZE: usize = 64 * 1024;
 
 #[derive(Parser)]
 struct Args {
@@ -33,21 +48,88 @@ enum Command {
 struct Client {
     /// The WebSocket server URL to connect to
     url: String,
+
+    /// Listen on this local address and forward each accepted TCP connection to a fresh
+    /// WebSocket, instead of bridging stdin/stdout
+    #[clap(short, long)]
+    listen: Option<SocketAddr>,
+
+    /// Multiplex every connection accepted under `--listen` over a single WebSocket instead of
+    /// opening one WebSocket per connection. Not supported together with `--keepalive`,
+    /// `--idle-timeout` or `--text`, which only apply to the non-multiplexed bridge
+    #[clap(
+        long,
+        requires = "listen",
+        conflicts_with_all = ["keepalive", "idle_timeout", "text"]
+    )]
+    mux: bool,
+
+    /// Send a WebSocket ping every this many seconds to keep idle tunnels alive
+    #[clap(long)]
+    keepalive: Option<u64>,
+
+    /// Close the connection if no traffic, including pongs, is seen for this many seconds
+    #[clap(long)]
+    idle_timeout: Option<u64>,
+
+    /// Base64-encode outgoing payloads into WebSocket text frames instead of binary frames, for
+    /// gateways that only reliably pass `Message::Text`
+    #[clap(long)]
+    text: bool,
+
+    /// Size, in bytes, of the read buffer used to copy data into each outgoing WebSocket message.
+    /// Not applied with `--mux`, whose channels read in fixed 1024-byte chunks
+    #[clap(long, default_value_t = DEFAULT_BUFFER_SIZE)]
+    buffer_size: usize,
 }
 
 #[derive(Parser)]
 struct Server {
     /// Bind address for the WebSocket server
+    #[cfg(not(feature = "socket-activation"))]
     #[clap(short, long, default_value = "127.0.0.1")]
     addr: String,
 
     /// Bind port for the WebSocket server
+    #[cfg(not(feature = "socket-activation"))]
     #[clap(short, long, default_value = "6449")]
     port: u16,
 
     /// The TCP socket to forward traffic to
     #[clap(short, long)]
     connect: String,
+
+    /// Path to a PEM-encoded TLS certificate chain; terminates TLS when paired with `--tls-key`
+    #[clap(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// Path to a PEM-encoded TLS private key; terminates TLS when paired with `--tls-cert`
+    #[clap(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Accept a mux-multiplexed WebSocket carrying many independent streams instead of treating
+    /// the connection as a single plain tunnel. Not supported together with `--keepalive`,
+    /// `--idle-timeout` or `--text`, which only apply to the non-multiplexed bridge
+    #[clap(long, conflicts_with_all = ["keepalive", "idle_timeout", "text"])]
+    mux: bool,
+
+    /// Send a WebSocket ping every this many seconds to keep idle tunnels alive
+    #[clap(long)]
+    keepalive: Option<u64>,
+
+    /// Close the connection if no traffic, including pongs, is seen for this many seconds
+    #[clap(long)]
+    idle_timeout: Option<u64>,
+
+    /// Base64-encode outgoing payloads into WebSocket text frames instead of binary frames, for
+    /// gateways that only reliably pass `Message::Text`
+    #[clap(long)]
+    text: bool,
+
+    /// Size, in bytes, of the read buffer used to copy data into each outgoing WebSocket message.
+    /// Not applied with `--mux`, whose channels read in fixed 1024-byte chunks
+    #[clap(long, default_value_t = DEFAULT_BUFFER_SIZE)]
+    buffer_size: usize,
 }
 
 fn main() -> Result<(), anyhow::Error> {
@@ -64,46 +146,115 @@ fn main() -> Result<(), anyhow::Error> {
 
 impl Client {
     async fn run(self) -> Result<(), anyhow::Error> {
-        let (mut stream, _) = connect_async_with_config(self.url, None, true).await?;
-        let mut stdin = tokio::io::stdin();
-        let mut stdout = tokio::io::stdout();
-        let mut buffer = vec![0; BUFFER_SIZE];
-        loop {
-            select! {
-                msg = stream.next() => {
-                    let msg = match msg {
-                        None => break,
-                        Some(Err(e)) => return Err(e.into()),
-                        Some(Ok(Message::Close(_))) => break,
-                        Some(Ok(Message::Ping(p))) => {
-                            stream.send(Message::Pong(p)).await?;
-                            continue;
-                        }
-                        Some(Ok(Message::Binary(msg))) => msg,
-                        Some(Ok(_)) => continue,
-                    };
-                    stdout.write_all(&msg).await?;
-                }
-                size = stdin.read(&mut buffer) => {
-                    let size = size?;
-                    if size == 0 {
-                        break;
-                    }
-                    stream.send(Message::Binary(buffer[..size].to_vec().into())).await?;
+        match self.listen {
+            Some(listen) => self.run_listener(listen).await,
+            None => self.run_stdio().await,
+        }
+    }
+
+    /// Bridges a single WebSocket connection to stdin/stdout, `ProxyCommand`-style.
+    async fn run_stdio(self) -> Result<(), anyhow::Error> {
+        let stream = connect_websocket(&self.url).await?;
+        let io = tokio::io::join(tokio::io::stdin(), tokio::io::stdout());
+        bridge(
+            stream,
+            io,
+            secs(self.keepalive),
+            secs(self.idle_timeout),
+            self.text,
+            self.buffer_size,
+        )
+        .await
+    }
+
+    /// Accepts local TCP connections and bridges each one to its own WebSocket, `ssh -L`-style,
+    /// or to a single shared mux-multiplexed WebSocket when `--mux` is set.
+    async fn run_listener(self, listen: SocketAddr) -> Result<(), anyhow::Error> {
+        let listener = TcpListener::bind(listen).await?;
+        if self.mux {
+            let stream = connect_websocket(&self.url).await?;
+            return mux::run_client(stream, listener).await;
+        }
+        let keepalive = secs(self.keepalive);
+        let idle_timeout = secs(self.idle_timeout);
+        let text = self.text;
+        let buffer_size = self.buffer_size;
+        while let Ok((tcp, _)) = listener.accept().await {
+            let url = self.url.clone();
+            spawn(async move {
+                let result =
+                    forward_local(tcp, &url, keepalive, idle_timeout, text, buffer_size).await;
+                if let Err(e) = result {
+                    tracing::warn!("Error while forwarding: {}", e);
                 }
-            }
+            });
         }
         Ok(())
     }
 }
 
+/// Converts a `--keepalive`/`--idle-timeout` seconds value into a `Duration`.
+fn secs(value: Option<u64>) -> Option<Duration> {
+    value.map(Duration::from_secs)
+}
+
+/// Connects to `url`, negotiating TLS when it uses the `wss://` scheme.
+async fn connect_websocket(
+    url: &str,
+) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, anyhow::Error> {
+    let connector = if url.starts_with("wss://") {
+        Some(Connector::Rustls(Arc::new(tls_client_config()?)))
+    } else {
+        None
+    };
+    let (stream, _) = connect_async_tls_with_config(url, None, true, connector).await?;
+    Ok(stream)
+}
+
+/// Opens a fresh WebSocket to `url` and bridges it to an accepted local TCP connection.
+async fn forward_local(
+    tcp: TcpStream,
+    url: &str,
+    keepalive: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    text: bool,
+    buffer_size: usize,
+) -> Result<(), anyhow::Error> {
+    tcp.set_nodelay(true)?;
+    let stream = connect_websocket(url).await?;
+    bridge(stream, tcp, keepalive, idle_timeout, text, buffer_size).await
+}
+
 impl Server {
     async fn run(self) -> Result<(), anyhow::Error> {
-        let listener = TcpListener::bind((self.addr, self.port)).await?;
+        let listener = bind_listener(&self).await?;
         let addr = self.connect.parse()?;
+        let acceptor = match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => Some(tls_acceptor(cert, key)?),
+            _ => None,
+        };
+        let mux = self.mux;
+        let keepalive = secs(self.keepalive);
+        let idle_timeout = secs(self.idle_timeout);
+        let text = self.text;
+        let buffer_size = self.buffer_size;
         while let Ok((stream, _)) = listener.accept().await {
+            let acceptor = acceptor.clone();
             spawn(async move {
-                if let Err(e) = forward(stream, addr).await {
+                let result = match acceptor {
+                    Some(acceptor) => match acceptor.accept(stream).await {
+                        Ok(stream) => {
+                            forward(stream, addr, mux, keepalive, idle_timeout, text, buffer_size)
+                                .await
+                        }
+                        Err(e) => Err(e.into()),
+                    },
+                    None => {
+                        forward(stream, addr, mux, keepalive, idle_timeout, text, buffer_size)
+                            .await
+                    }
+                };
+                if let Err(e) = result {
                     tracing::warn!("Error while forwarding: {}", e);
                 }
             });
@@ -112,35 +263,233 @@ impl Server {
     }
 }
 
-async fn forward(stream: TcpStream, addr: SocketAddr) -> Result<(), anyhow::Error> {
-    let mut inbound = accept_async(stream).await?;
-    let mut outbound = TcpStream::connect(addr).await?;
+/// Binds the server's listening socket from `--addr`/`--port`.
+#[cfg(not(feature = "socket-activation"))]
+async fn bind_listener(server: &Server) -> Result<TcpListener, anyhow::Error> {
+    Ok(TcpListener::bind((server.addr.clone(), server.port)).await?)
+}
+
+/// Adopts the listening socket handed over by systemd socket activation (`LISTEN_FDS`, fd 3)
+/// instead of binding one, so the process can run privilege-dropped and on-demand.
+#[cfg(feature = "socket-activation")]
+async fn bind_listener(_server: &Server) -> Result<TcpListener, anyhow::Error> {
+    use std::os::fd::FromRawFd;
+
+    const LISTEN_FDS_START: std::os::fd::RawFd = 3;
+
+    let pid: u32 = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse().ok())
+        .ok_or_else(|| anyhow::anyhow!("LISTEN_PID not set; no socket was passed by systemd"))?;
+    if pid != std::process::id() {
+        anyhow::bail!(
+            "LISTEN_PID ({}) does not match this process ({}); socket was not meant for us",
+            pid,
+            std::process::id()
+        );
+    }
+    let fds: u32 = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|fds| fds.parse().ok())
+        .ok_or_else(|| anyhow::anyhow!("LISTEN_FDS not set; no socket was passed by systemd"))?;
+    if fds < 1 {
+        anyhow::bail!("LISTEN_FDS is {}, expected at least 1 socket", fds);
+    }
+
+    let std_listener = unsafe { std::net::TcpListener::from_raw_fd(LISTEN_FDS_START) };
+    std_listener.set_nonblocking(true)?;
+    Ok(TcpListener::from_std(std_listener)?)
+}
+
+/// Builds a rustls client config trusting the platform's native certificate store.
+fn tls_client_config() -> Result<rustls::ClientConfig, anyhow::Error> {
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in rustls_native_certs::load_native_certs()? {
+        roots.add(cert)?;
+    }
+    Ok(rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
+
+/// Builds a TLS acceptor from a PEM certificate chain and private key for TLS termination.
+fn tls_acceptor(cert: &Path, key: &Path) -> Result<tokio_rustls::TlsAcceptor, anyhow::Error> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    let private_key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key)?))?
+        .ok_or_else(|| anyhow::anyhow!("no private key found in {}", key.display()))?;
+    let config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, private_key)?;
+    Ok(tokio_rustls::TlsAcceptor::from(Arc::new(config)))
+}
+
+async fn forward<S>(
+    stream: S,
+    addr: SocketAddr,
+    mux: bool,
+    keepalive: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    text: bool,
+    buffer_size: usize,
+) -> Result<(), anyhow::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let inbound = accept_async(stream).await?;
+    if mux {
+        return mux::run_server(inbound, addr).await;
+    }
+    let outbound = TcpStream::connect(addr).await?;
     outbound.set_nodelay(true)?;
+    bridge(inbound, outbound, keepalive, idle_timeout, text, buffer_size).await
+}
+
+/// Bridges a WebSocket and a plain duplex stream by splitting both into independent halves and
+/// running the two directions as concurrent tasks, so neither direction head-of-line-blocks the
+/// other. When `keepalive` is set, a ping is sent on that interval; when `idle_timeout` is set,
+/// the connection is closed once that long passes without any traffic, including pongs. When
+/// `text` is set, outgoing payloads are base64-encoded into `Message::Text` frames for gateways
+/// that mangle binary frames; incoming `Message::Binary` is still accepted for interoperability.
+async fn bridge<S, T>(
+    ws: WebSocketStream<S>,
+    io: T,
+    keepalive: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    text: bool,
+    buffer_size: usize,
+) -> Result<(), anyhow::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (ws_sink, ws_stream) = ws.split();
+    let (io_read, io_write) = tokio::io::split(io);
+    let ws_sink = Arc::new(Mutex::new(ws_sink));
+    let last_activity = Arc::new(Mutex::new(Instant::now()));
+
+    let mut reader = spawn(copy_ws_to_io(
+        ws_stream,
+        io_write,
+        ws_sink.clone(),
+        last_activity.clone(),
+    ));
+    let mut writer = spawn(copy_io_to_ws(
+        io_read,
+        ws_sink,
+        text,
+        buffer_size,
+        keepalive,
+        idle_timeout,
+        last_activity,
+    ));
+
+    // Whichever direction finishes first ends the bridge; abort the other so a closed
+    // WebSocket or a closed TCP connection doesn't leave its sibling task reading forever.
+    let result = select! {
+        result = &mut reader => { writer.abort(); result }
+        result = &mut writer => { reader.abort(); result }
+    };
+    match result {
+        Ok(result) => result,
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Reads frames off the WebSocket and writes their payload to `io_write`, replying to pings on
+/// the shared sink and recording each frame as activity for the idle timeout.
+async fn copy_ws_to_io<S, W>(
+    mut ws_stream: SplitStream<WebSocketStream<S>>,
+    mut io_write: W,
+    ws_sink: Arc<Mutex<SplitSink<WebSocketStream<S>, Message>>>,
+    last_activity: Arc<Mutex<Instant>>,
+) -> Result<(), anyhow::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    while let Some(msg) = ws_stream.next().await {
+        *last_activity.lock().await = Instant::now();
+        let msg = match msg? {
+            Message::Close(_) => break,
+            Message::Ping(p) => {
+                ws_sink.lock().await.send(Message::Pong(p)).await?;
+                continue;
+            }
+            Message::Binary(msg) => msg,
+            Message::Text(msg) => base64::engine::general_purpose::STANDARD.decode(msg.as_str())?,
+            _ => continue,
+        };
+        io_write.write_all(&msg).await?;
+    }
+    Ok(())
+}
+
+/// Reads bytes off `io_read` and forwards each chunk as a WebSocket message on the shared sink,
+/// also driving the keepalive ping interval and the idle timeout.
+async fn copy_io_to_ws<S, R>(
+    mut io_read: R,
+    ws_sink: Arc<Mutex<SplitSink<WebSocketStream<S>, Message>>>,
+    text: bool,
+    buffer_size: usize,
+    keepalive: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    last_activity: Arc<Mutex<Instant>>,
+) -> Result<(), anyhow::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    R: AsyncRead + Unpin,
+{
+    let mut buffer = vec![0; buffer_size];
+    let mut keepalive_ticker = keepalive.map(time::interval);
     loop {
+        let idle_deadline = async {
+            match idle_timeout {
+                Some(timeout) => {
+                    let elapsed = last_activity.lock().await.elapsed();
+                    time::sleep(timeout.saturating_sub(elapsed)).await
+                }
+                None => std::future::pending().await,
+            }
+        };
         select! {
-            msg = inbound.next() => {
-                let msg = match msg {
-                    None => break,
-                    Some(Err(e)) => return Err(e.into()),
-                    Some(Ok(Message::Close(_))) => break,
-                    Some(Ok(Message::Ping(p))) => {
-                        inbound.send(Message::Pong(p)).await?;
-                        continue;
-                    }
-                    Some(Ok(Message::Binary(msg))) => msg,
-                    Some(Ok(_)) => continue,
+            size = io_read.read(&mut buffer) => {
+                let size = size?;
+                if size == 0 {
+                    break;
+                }
+                *last_activity.lock().await = Instant::now();
+                let msg = if text {
+                    Message::Text(base64::engine::general_purpose::STANDARD.encode(&buffer[..size]))
+                } else {
+                    Message::Binary(buffer[..size].to_vec())
                 };
-                outbound.write_all(&msg).await?;
+                ws_sink.lock().await.send(msg).await?;
+            }
+            _ = tick(&mut keepalive_ticker) => {
+                ws_sink.lock().await.send(Message::Ping(Vec::new())).await?;
             }
-            _ = outbound.readable() => {
-                let mut buf = vec![0; BUFFER_SIZE];
-                let n = outbound.read(&mut buf).await?;
-                if n == 0 {
+            _ = idle_deadline => {
+                // The sleep was armed with a snapshot of `last_activity`; the other
+                // direction may have recorded fresher activity while we slept, so
+                // re-check before treating this as a real timeout.
+                let elapsed = last_activity.lock().await.elapsed();
+                if idle_timeout.is_some_and(|timeout| elapsed >= timeout) {
+                    tracing::info!("idle timeout reached, closing connection");
                     break;
                 }
-                inbound.send(Message::Binary(buf[..n].to_vec().into())).await?;
             }
         }
     }
     Ok(())
 }
+
+/// Awaits the next tick of an optional interval, never resolving when there is none.
+async fn tick(interval: &mut Option<time::Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
@@ -0,0 +1,274 @@
+//! Stream-multiplexing protocol (`--mux`) that lets a single WebSocket carry many
+//! independent TCP streams instead of opening one WebSocket per connection.
+//!
+//! Every multiplexed message is a `Message::Binary` prefixed with a small frame header:
+//! a big-endian `u32` channel id, a `u8` opcode (`Open`, `Data`, `Close`), then the payload.
+
+use anyhow::Error;
+use futures_util::{SinkExt, StreamExt};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::select;
+use tokio::spawn;
+use tokio::sync::{mpsc, Mutex};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+use crate::BUFFER_SIZE;
+
+const FRAME_HEADER_LEN: usize = 5;
+const OUT_CHANNEL_SIZE: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Opcode {
+    Open,
+    Data,
+    Close,
+}
+
+impl Opcode {
+    fn from_u8(b: u8) -> Option<Self> {
+        match b {
+            0 => Some(Opcode::Open),
+            1 => Some(Opcode::Data),
+            2 => Some(Opcode::Close),
+            _ => None,
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            Opcode::Open => 0,
+            Opcode::Data => 1,
+            Opcode::Close => 2,
+        }
+    }
+}
+
+/// Channels indexed by id, each holding the sender half that feeds its TCP-side task. The
+/// sender is unbounded so the shared demux loop below can always hand off a `Data` frame
+/// without blocking on it or dropping it, even while that channel's own TCP peer is slow to
+/// drain; backpressure for a stalled channel is absorbed by this queue instead of stalling
+/// every other multiplexed channel.
+type Channels = Arc<Mutex<HashMap<u32, mpsc::UnboundedSender<Vec<u8>>>>>;
+
+fn encode(channel: u32, op: Opcode, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+    frame.extend_from_slice(&channel.to_be_bytes());
+    frame.push(op.to_u8());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn decode(frame: &[u8]) -> Result<(u32, Opcode, &[u8]), Error> {
+    if frame.len() < FRAME_HEADER_LEN {
+        anyhow::bail!("mux frame too short: {} bytes", frame.len());
+    }
+    let channel = u32::from_be_bytes(frame[..4].try_into().unwrap());
+    let op = Opcode::from_u8(frame[4])
+        .ok_or_else(|| anyhow::anyhow!("unknown mux opcode {}", frame[4]))?;
+    Ok((channel, op, &frame[FRAME_HEADER_LEN..]))
+}
+
+/// Runs the client side of the mux protocol: accepts local TCP connections from `listener`,
+/// each becoming a new channel `Open`ed over `ws`, and demuxes incoming `Data`/`Close` frames
+/// back to the matching connection.
+pub async fn run_client<S>(mut ws: WebSocketStream<S>, listener: TcpListener) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (out_tx, mut out_rx) = mpsc::channel::<Vec<u8>>(OUT_CHANNEL_SIZE);
+    let channels: Channels = Arc::new(Mutex::new(HashMap::new()));
+    let mut next_channel: u32 = 0;
+
+    loop {
+        select! {
+            accepted = listener.accept() => {
+                let (tcp, _) = accepted?;
+                let channel = next_channel;
+                next_channel = next_channel.wrapping_add(1);
+                let (tx, rx) = mpsc::unbounded_channel();
+                channels.lock().await.insert(channel, tx);
+                out_tx.send(encode(channel, Opcode::Open, &[])).await?;
+                spawn(run_channel(tcp, channel, rx, out_tx.clone(), channels.clone()));
+            }
+            msg = ws.next() => {
+                if !handle_incoming(msg, &mut ws, &channels).await? {
+                    break;
+                }
+            }
+            frame = out_rx.recv() => {
+                match frame {
+                    Some(frame) => ws.send(Message::Binary(frame)).await?,
+                    None => break,
+                }
+            }
+        }
+    }
+    // The WebSocket is done; drop every channel's sender so its pump task's `rx.recv()`
+    // returns `None` and it stops, instead of leaking a task per idle TCP peer.
+    channels.lock().await.clear();
+    Ok(())
+}
+
+/// Runs the server side of the mux protocol over an already-upgraded WebSocket: on `Open`,
+/// connects a fresh TCP socket to `target` and registers it under the channel id; `Data` frames
+/// are demuxed to the matching socket and `Close` tears it down.
+pub async fn run_server<S>(mut ws: WebSocketStream<S>, target: SocketAddr) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (out_tx, mut out_rx) = mpsc::channel::<Vec<u8>>(OUT_CHANNEL_SIZE);
+    let channels: Channels = Arc::new(Mutex::new(HashMap::new()));
+
+    loop {
+        select! {
+            msg = ws.next() => {
+                let open_channel = match &msg {
+                    Some(Ok(Message::Binary(data))) => match decode(data) {
+                        Ok((channel, Opcode::Open, _)) => Some(channel),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                if let Some(channel) = open_channel {
+                    let (tx, rx) = mpsc::unbounded_channel();
+                    channels.lock().await.insert(channel, tx);
+                    spawn(connect_channel(target, channel, rx, out_tx.clone(), channels.clone()));
+                } else if !handle_incoming(msg, &mut ws, &channels).await? {
+                    break;
+                }
+            }
+            frame = out_rx.recv() => {
+                match frame {
+                    Some(frame) => ws.send(Message::Binary(frame)).await?,
+                    None => break,
+                }
+            }
+        }
+    }
+    // The WebSocket is done; drop every channel's sender so its pump task's `rx.recv()`
+    // returns `None` and it stops, instead of leaking a task per idle TCP peer.
+    channels.lock().await.clear();
+    Ok(())
+}
+
+/// Handles one message read from the shared WebSocket: replies to pings, demuxes `Data`/`Close`
+/// frames, and ignores everything else. Returns `Ok(false)` once the WebSocket is done.
+async fn handle_incoming<S>(
+    msg: Option<Result<Message, tokio_tungstenite::tungstenite::Error>>,
+    ws: &mut WebSocketStream<S>,
+    channels: &Channels,
+) -> Result<bool, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let data = match msg {
+        None => return Ok(false),
+        Some(Err(e)) => return Err(e.into()),
+        Some(Ok(Message::Close(_))) => return Ok(false),
+        Some(Ok(Message::Ping(p))) => {
+            ws.send(Message::Pong(p)).await?;
+            return Ok(true);
+        }
+        Some(Ok(Message::Binary(data))) => data,
+        Some(Ok(_)) => return Ok(true),
+    };
+    let (channel, op, payload) = decode(&data)?;
+    match op {
+        Opcode::Open => tracing::warn!("unexpected mux Open for channel {}", channel),
+        Opcode::Data => match channels.lock().await.get(&channel) {
+            // Unbounded and non-blocking: a stuck channel (its target not draining) must
+            // never stall this shared demux loop, and dropped bytes would corrupt the
+            // tunneled stream, so the frame is always handed off intact.
+            Some(tx) => {
+                if tx.send(payload.to_vec()).is_err() {
+                    tracing::warn!("mux channel {} closed, dropping data", channel);
+                }
+            }
+            None => tracing::warn!("mux data for unknown channel {}", channel),
+        },
+        Opcode::Close => {
+            channels.lock().await.remove(&channel);
+        }
+    }
+    Ok(true)
+}
+
+/// Bridges a locally-accepted TCP connection to its mux channel: bytes read from `tcp` are
+/// tagged `Data` frames pushed onto `out_tx`, and frames received for this channel are written
+/// back to `tcp`. Sends a `Close` frame and drops the channel entry once either side ends.
+async fn run_channel(
+    mut tcp: TcpStream,
+    channel: u32,
+    rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    out_tx: mpsc::Sender<Vec<u8>>,
+    channels: Channels,
+) {
+    tcp.set_nodelay(true).ok();
+    if let Err(e) = pump(&mut tcp, channel, rx, &out_tx).await {
+        tracing::warn!("mux channel {} error: {}", channel, e);
+    }
+    close_channel(channel, &out_tx, &channels).await;
+}
+
+/// Connects to `target` for a server-side channel, then bridges it the same way `run_channel`
+/// does on the client side, reporting connection failures as an immediate `Close`.
+async fn connect_channel(
+    target: SocketAddr,
+    channel: u32,
+    rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    out_tx: mpsc::Sender<Vec<u8>>,
+    channels: Channels,
+) {
+    let mut tcp = match TcpStream::connect(target).await {
+        Ok(tcp) => tcp,
+        Err(e) => {
+            tracing::warn!("mux channel {} connect error: {}", channel, e);
+            close_channel(channel, &out_tx, &channels).await;
+            return;
+        }
+    };
+    tcp.set_nodelay(true).ok();
+    if let Err(e) = pump(&mut tcp, channel, rx, &out_tx).await {
+        tracing::warn!("mux channel {} error: {}", channel, e);
+    }
+    close_channel(channel, &out_tx, &channels).await;
+}
+
+/// Removes a channel's entry and notifies the peer that it is closed.
+async fn close_channel(channel: u32, out_tx: &mpsc::Sender<Vec<u8>>, channels: &Channels) {
+    channels.lock().await.remove(&channel);
+    let _ = out_tx.send(encode(channel, Opcode::Close, &[])).await;
+}
+
+/// Shuttles bytes between one TCP socket and its mux channel until either side closes.
+async fn pump(
+    tcp: &mut TcpStream,
+    channel: u32,
+    mut rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    out_tx: &mpsc::Sender<Vec<u8>>,
+) -> Result<(), Error> {
+    let mut buffer = vec![0; BUFFER_SIZE];
+    loop {
+        select! {
+            data = rx.recv() => {
+                match data {
+                    Some(data) => tcp.write_all(&data).await?,
+                    None => break,
+                }
+            }
+            size = tcp.read(&mut buffer) => {
+                let size = size?;
+                if size == 0 {
+                    break;
+                }
+                out_tx.send(encode(channel, Opcode::Data, &buffer[..size])).await?;
+            }
+        }
+    }
+    Ok(())
+}